@@ -0,0 +1,389 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! incrementally assembles a `Template` from a raw `bitcoin::Transaction`
+use super::output::Output;
+use super::{Template, TemplateMetadata, TemplatePsbtError};
+use bitcoin::consensus::encode::Encodable;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::Transaction;
+use sapio_base::Clause;
+use std::fmt;
+
+/// Errors that can arise while assembling a `Template` via `Builder`
+#[derive(Debug)]
+pub enum TemplateBuildError {
+    /// the requested CTV input index is out of range for `tx.input`
+    CTVIndexOutOfBounds {
+        /// the index that was requested
+        index: u32,
+        /// the number of inputs actually present on the transaction
+        n_inputs: usize,
+    },
+    /// the `outputs` metadata vec doesn't correspond to `tx.output` -- the
+    /// thing `get_ctv_hash` actually commits to. Either they have different
+    /// lengths, or `outputs[index]`'s script_pubkey/amount differs from
+    /// `tx.output[index]`'s.
+    OutputsMismatch {
+        /// the first index at which `outputs` and `tx.output` disagree, or
+        /// `None` if they simply have different lengths
+        index: Option<usize>,
+        /// the number of `outputs` metadata entries
+        n_outputs: usize,
+        /// the number of outputs actually present on `tx`
+        n_tx_outputs: usize,
+    },
+    /// building the resulting PSBT failed
+    Psbt(TemplatePsbtError),
+    /// exporting the resulting Stratum V2 coinbase outputs failed
+    Stratum(super::stratum::StratumExportError),
+}
+
+impl fmt::Display for TemplateBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateBuildError::CTVIndexOutOfBounds { index, n_inputs } => write!(
+                f,
+                "ctv_index {} is out of bounds for a transaction with {} input(s)",
+                index, n_inputs
+            ),
+            TemplateBuildError::OutputsMismatch {
+                index: Some(index), ..
+            } => write!(
+                f,
+                "outputs[{}] doesn't match tx.output[{}]'s script_pubkey/amount",
+                index, index
+            ),
+            TemplateBuildError::OutputsMismatch {
+                index: None,
+                n_outputs,
+                n_tx_outputs,
+            } => write!(
+                f,
+                "{} outputs were added via add_output, but tx.output has {}",
+                n_outputs, n_tx_outputs
+            ),
+            TemplateBuildError::Psbt(e) => write!(f, "{}", e),
+            TemplateBuildError::Stratum(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateBuildError {}
+
+impl From<TemplatePsbtError> for TemplateBuildError {
+    fn from(e: TemplatePsbtError) -> Self {
+        TemplateBuildError::Psbt(e)
+    }
+}
+
+impl From<super::stratum::StratumExportError> for TemplateBuildError {
+    fn from(e: super::stratum::StratumExportError) -> Self {
+        TemplateBuildError::Stratum(e)
+    }
+}
+
+/// Builds a `Template` up incrementally from a `bitcoin::Transaction` skeleton,
+/// a set of `Output`s, and any additional `Clause` guards.
+#[derive(Clone, Debug)]
+pub struct Builder {
+    tx: Transaction,
+    guards: Vec<Clause>,
+    outputs: Vec<Output>,
+    /// the input index the CTV hash should be computed against. Defaults to 0
+    /// if left unset.
+    ctv_index: Option<u32>,
+    min_feerate_sats_vbyte: Option<Amount>,
+    metadata_map_s2s: TemplateMetadata,
+    /// the notional input value funding this template. Defaults to the sum
+    /// of `outputs` (i.e. a zero-fee template) if left unset.
+    max_amount: Option<Amount>,
+}
+
+impl Builder {
+    /// start a new `Builder` from a transaction skeleton
+    pub fn new(tx: Transaction) -> Self {
+        Builder {
+            tx,
+            guards: vec![],
+            outputs: vec![],
+            ctv_index: None,
+            min_feerate_sats_vbyte: None,
+            metadata_map_s2s: TemplateMetadata::new(),
+            max_amount: None,
+        }
+    }
+
+    /// append an `Output` to the set of outputs this template will pay
+    pub fn add_output(mut self, output: Output) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    /// add an additional spending `Clause` this template must satisfy
+    pub fn add_guard(mut self, guard: Clause) -> Self {
+        self.guards.push(guard);
+        self
+    }
+
+    /// set the minimum feerate this template must pay, in sats/vbyte
+    pub fn set_min_feerate(mut self, feerate: Amount) -> Self {
+        self.min_feerate_sats_vbyte = Some(feerate);
+        self
+    }
+
+    /// set the notional amount of the funding input paying into this
+    /// template. Use this when the template should pay a fee -- i.e. when
+    /// `amount` is greater than the sum of `outputs`. If left unset, the
+    /// funding amount is assumed to exactly match the sum of `outputs`
+    /// (a zero-fee template).
+    pub fn set_max_amount(mut self, amount: Amount) -> Self {
+        self.max_amount = Some(amount);
+        self
+    }
+
+    /// attach metadata to the resulting `Template`
+    pub fn set_metadata(mut self, metadata_map_s2s: TemplateMetadata) -> Self {
+        self.metadata_map_s2s = metadata_map_s2s;
+        self
+    }
+
+    /// set the input index the StandardTemplateHash should be computed over.
+    /// Returns `Err` if `index` does not refer to an input present on `tx`.
+    pub fn set_ctv_index(mut self, index: u32) -> Result<Self, TemplateBuildError> {
+        if index as usize >= self.tx.input.len() {
+            return Err(TemplateBuildError::CTVIndexOutOfBounds {
+                index,
+                n_inputs: self.tx.input.len(),
+            });
+        }
+        self.ctv_index = Some(index);
+        Ok(self)
+    }
+
+    /// check that `outputs` corresponds, index for index, to `tx.output` --
+    /// the thing `get_ctv_hash` actually commits to -- so every other
+    /// accessor (`total_amount`, `fee`, `to_psbt`, ...) can trust that it's
+    /// describing the same outputs the CTV hash covers.
+    fn check_outputs_match_tx(&self) -> Result<(), TemplateBuildError> {
+        if self.outputs.len() != self.tx.output.len() {
+            return Err(TemplateBuildError::OutputsMismatch {
+                index: None,
+                n_outputs: self.outputs.len(),
+                n_tx_outputs: self.tx.output.len(),
+            });
+        }
+        for (index, (output, txout)) in self.outputs.iter().zip(self.tx.output.iter()).enumerate() {
+            if output.script_pubkey != txout.script_pubkey || output.amount.as_sat() != txout.value
+            {
+                return Err(TemplateBuildError::OutputsMismatch {
+                    index: Some(index),
+                    n_outputs: self.outputs.len(),
+                    n_tx_outputs: self.tx.output.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// finalize this `Builder` into a `Template`, computing its CTV hash
+    pub fn build(self) -> Result<Template, TemplateBuildError> {
+        let ctv_index = self.ctv_index.unwrap_or(0);
+        if ctv_index as usize >= self.tx.input.len() {
+            return Err(TemplateBuildError::CTVIndexOutOfBounds {
+                index: ctv_index,
+                n_inputs: self.tx.input.len(),
+            });
+        }
+        self.check_outputs_match_tx()?;
+        let ctv = get_ctv_hash(&self.tx, ctv_index);
+        let outputs_total = self
+            .outputs
+            .iter()
+            .map(|o| o.amount)
+            .fold(Amount::from_sat(0), |a, b| a + b);
+        let max = self.max_amount.unwrap_or(outputs_total);
+        Ok(Template {
+            guards: self.guards,
+            ctv,
+            ctv_index,
+            max,
+            min_feerate_sats_vbyte: self.min_feerate_sats_vbyte,
+            metadata_map_s2s: self.metadata_map_s2s,
+            tx: self.tx,
+            outputs: self.outputs,
+        })
+    }
+
+    /// finalize this `Builder` directly into a PSBT, as `build().to_psbt()`
+    /// would. See `Template::to_psbt` for details.
+    pub fn to_psbt(self) -> Result<PartiallySignedTransaction, TemplateBuildError> {
+        Ok(self.build()?.to_psbt()?)
+    }
+}
+
+/// Computes the BIP-119 DefaultCheckTemplateVerifyHash of `tx` as it would be
+/// spent at `input_index`. This is a sha256 (not double-sha256) over, in
+/// order: nVersion, nLockTime, the sha256 of all scriptSigs (only if any
+/// input has a non-empty scriptSig), the input count, the sha256 of all
+/// nSequences, the output count, the sha256 of all serialized outputs, and
+/// finally the spending input index.
+pub(crate) fn get_ctv_hash(tx: &Transaction, input_index: u32) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    tx.version
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    tx.lock_time
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+
+    if tx.input.iter().any(|i| !i.script_sig.is_empty()) {
+        let mut script_sigs = sha256::Hash::engine();
+        for txin in tx.input.iter() {
+            txin.script_sig
+                .consensus_encode(&mut script_sigs)
+                .expect("engines don't error");
+        }
+        sha256::Hash::from_engine(script_sigs)
+            .consensus_encode(&mut engine)
+            .expect("engines don't error");
+    }
+
+    (tx.input.len() as u32)
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    {
+        let mut sequences = sha256::Hash::engine();
+        for txin in tx.input.iter() {
+            txin.sequence
+                .consensus_encode(&mut sequences)
+                .expect("engines don't error");
+        }
+        sha256::Hash::from_engine(sequences)
+            .consensus_encode(&mut engine)
+            .expect("engines don't error");
+    }
+
+    (tx.output.len() as u32)
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    {
+        let mut outputs = sha256::Hash::engine();
+        for txout in tx.output.iter() {
+            txout
+                .consensus_encode(&mut outputs)
+                .expect("engines don't error");
+        }
+        sha256::Hash::from_engine(outputs)
+            .consensus_encode(&mut engine)
+            .expect("engines don't error");
+    }
+
+    input_index
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+
+    sha256::Hash::from_engine(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut, Witness};
+
+    fn sample_tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFF,
+                    witness: Witness::default(),
+                },
+                TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFE,
+                    witness: Witness::default(),
+                },
+            ],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    /// independently assembles the exact BIP-119 byte layout (without going
+    /// through `get_ctv_hash`) and checks the two agree, at a non-zero index.
+    #[test]
+    fn get_ctv_hash_matches_manual_bip119_serialization_at_nonzero_index() {
+        let tx = sample_tx();
+        let index = 1u32;
+
+        let mut buf = Vec::new();
+        tx.version.consensus_encode(&mut buf).unwrap();
+        tx.lock_time.consensus_encode(&mut buf).unwrap();
+        // no input has a non-empty scriptSig, so the scriptSigs hash is omitted
+        (tx.input.len() as u32).consensus_encode(&mut buf).unwrap();
+        let sequences_hash = {
+            let mut s = Vec::new();
+            for txin in &tx.input {
+                txin.sequence.consensus_encode(&mut s).unwrap();
+            }
+            sha256::Hash::hash(&s)
+        };
+        sequences_hash.consensus_encode(&mut buf).unwrap();
+        (tx.output.len() as u32).consensus_encode(&mut buf).unwrap();
+        let outputs_hash = {
+            let mut s = Vec::new();
+            for txout in &tx.output {
+                txout.consensus_encode(&mut s).unwrap();
+            }
+            sha256::Hash::hash(&s)
+        };
+        outputs_hash.consensus_encode(&mut buf).unwrap();
+        index.consensus_encode(&mut buf).unwrap();
+        let expected = sha256::Hash::hash(&buf);
+
+        assert_eq!(get_ctv_hash(&tx, index), expected);
+        // and committing to a different spending index must change the hash
+        assert_ne!(get_ctv_hash(&tx, index), get_ctv_hash(&tx, 0));
+    }
+
+    #[test]
+    fn set_ctv_index_rejects_index_past_last_input() {
+        let tx = sample_tx();
+        let err = Builder::new(tx).set_ctv_index(2).unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateBuildError::CTVIndexOutOfBounds {
+                index: 2,
+                n_inputs: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn build_rejects_default_ctv_index_on_an_inputless_transaction() {
+        // with no `set_ctv_index` call, `build()` still defaults to index 0
+        // and must reject a transaction that has no input 0 to commit to.
+        let mut tx = sample_tx();
+        tx.input.clear();
+        let err = Builder::new(tx).build().unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateBuildError::CTVIndexOutOfBounds {
+                index: 0,
+                n_inputs: 0
+            }
+        ));
+    }
+}
@@ -7,10 +7,12 @@
 //! utilities for building Bitcoin transaction templates up programmatically
 use bitcoin::hashes::sha256;
 use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::{self, PartiallySignedTransaction};
 use sapio_base::Clause;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 
 pub mod output;
 pub use output::{Output, OutputMeta};
@@ -18,6 +20,12 @@ pub use output::{Output, OutputMeta};
 pub mod builder;
 pub use builder::Builder;
 
+pub mod stratum;
+pub use stratum::StratumExportError;
+
+#[cfg(feature = "rpc-submission")]
+pub mod submission;
+
 /// Metadata Struct which has some standard defined fields
 /// and can be extended via a hashmap
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
@@ -58,11 +66,13 @@ pub struct Template {
     /// the precomputed template hash for this Template
     #[serde(rename = "precomputed_template_hash")]
     pub ctv: sha256::Hash,
-    /// the index used for the template hash. (TODO: currently always 0, although
-    /// future version may support other indexes)
+    /// the input index the template hash was computed against. Set via
+    /// `builder::Builder::set_ctv_index`; defaults to 0 when left unset.
     #[serde(rename = "precomputed_template_hash_idx")]
     pub ctv_index: u32,
-    /// the amount being sent to this Template (TODO: currently computed via tx.total_amount())
+    /// the notional amount of the funding input paying into this Template.
+    /// Defaults to `tx.output`'s total value (a zero-fee template) unless
+    /// overridden via `builder::Builder::set_max_amount`.
     #[serde(
         rename = "max_amount_sats",
         with = "bitcoin::util::amount::serde::as_sat"
@@ -105,4 +115,329 @@ impl Template {
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
     }
+
+    /// the absolute fee this template pays, i.e. the notional funding amount
+    /// (`max`) minus the total sent to `outputs`. Saturates to zero rather
+    /// than underflowing if `max` doesn't cover `total_amount()`.
+    pub fn fee(&self) -> Amount {
+        self.max
+            .checked_sub(self.total_amount())
+            .unwrap_or(Amount::from_sat(0))
+    }
+
+    /// the virtual weight of `tx`, in weight units (BIP-141 `WU`), as it
+    /// would be broadcast today -- i.e. before a wallet fills in `tx.input`.
+    pub fn weight(&self) -> u64 {
+        self.tx.get_weight() as u64
+    }
+
+    /// the total sigop cost of `tx`, in the same weighted units as
+    /// BIP-141's `GetTransactionSigOpCost`: legacy sigops (from `scriptSig`s
+    /// and output `scriptPubkey`s) count 4x, witness sigops count 1x. A
+    /// witness sigop count requires the *input*'s prevout `scriptPubkey`
+    /// (or its witness/redeem script), which isn't knowable before this
+    /// Template is funded -- `outputs[..].witness_script` belongs to a
+    /// future child transaction that later spends these outputs, not to
+    /// this one, and attributing it here would both mis-attribute and
+    /// double-count it once that child is itself turned into a `Template`.
+    /// So, like `weight()`, this only accounts for what's knowable from
+    /// `tx` today and reports a witness contribution of 0.
+    pub fn sigops_cost(&self) -> u64 {
+        let legacy: u64 = self
+            .tx
+            .input
+            .iter()
+            .map(|i| script_sigop_count(&i.script_sig, false))
+            .sum::<u64>()
+            + self
+                .tx
+                .output
+                .iter()
+                .map(|o| script_sigop_count(&o.script_pubkey, false))
+                .sum::<u64>();
+        legacy * 4
+    }
+
+    /// the feerate this template pays, in sats/vbyte, derived from `fee()`
+    /// and `weight()`. Compare against `min_feerate_sats_vbyte` to reject an
+    /// underpaying template.
+    pub fn feerate_sats_per_vbyte(&self) -> f64 {
+        feerate_sats_per_vbyte(self.fee(), self.weight())
+    }
+
+    /// `true` if this template either has no `min_feerate_sats_vbyte` set, or
+    /// pays at least that feerate.
+    pub fn meets_min_feerate(&self) -> bool {
+        match self.min_feerate_sats_vbyte {
+            None => true,
+            Some(min) => self.feerate_sats_per_vbyte() >= min.as_sat() as f64,
+        }
+    }
+
+    /// view of this Template annotated with its `fee()`, `weight()`, and
+    /// `sigops_cost()`, suitable for serializing alongside the template's
+    /// own fields (akin to a `getblocktemplate` transaction entry).
+    pub fn annotated(&self) -> AnnotatedTemplate {
+        AnnotatedTemplate {
+            fee_sats: self.fee().as_sat(),
+            weight_wu: self.weight(),
+            sigops_cost: self.sigops_cost(),
+            template: self,
+        }
+    }
+
+    /// Emit this Template as a BIP-174 `PartiallySignedTransaction`, with
+    /// per-output witness/redeem scripts populated from `outputs`. Inputs are
+    /// left unfunded -- a wallet is expected to add the UTXO(s) satisfying
+    /// `total_amount()` and sign for it.
+    ///
+    /// (TODO: this only covers segwit v0 outputs, since `Output` only carries
+    /// `witness_script`/`redeem_script`; a taproot output's `tap_internal_key`
+    /// and `tap_tree` are not yet populated. `guards` is also not mapped onto
+    /// the PSBT -- `Clause` has no general encoding into PSBT proprietary/
+    /// sighash fields yet, so a guard that needs e.g. a specific sighash type
+    /// communicated to a signer is not yet represented here.)
+    pub fn to_psbt(&self) -> Result<PartiallySignedTransaction, TemplatePsbtError> {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(self.tx.clone())?;
+        for (psbt_output, output) in psbt.outputs.iter_mut().zip(self.outputs.iter()) {
+            psbt_output.witness_script = output.witness_script.clone();
+            psbt_output.redeem_script = output.redeem_script.clone();
+        }
+        Ok(psbt)
+    }
+}
+
+/// compute a feerate in sats/vbyte from a `fee` and a `weight` in weight
+/// units, without dividing by zero for a (practically unreachable, but
+/// defended against) zero-weight transaction.
+fn feerate_sats_per_vbyte(fee: Amount, weight: u64) -> f64 {
+    let vsize = weight as f64 / 4.0;
+    if vsize == 0.0 {
+        0.0
+    } else {
+        fee.as_sat() as f64 / vsize
+    }
+}
+
+/// A `Template` annotated with the fee/weight/sigop accounting a
+/// `getblocktemplate`-style consumer needs to schedule or batch it, without
+/// changing `Template`'s own on-the-wire representation.
+#[derive(Serialize)]
+pub struct AnnotatedTemplate<'a> {
+    #[serde(flatten)]
+    pub template: &'a Template,
+    /// see `Template::fee`
+    pub fee_sats: u64,
+    /// see `Template::weight`
+    pub weight_wu: u64,
+    /// see `Template::sigops_cost`
+    pub sigops_cost: u64,
+}
+
+/// count the sigops in `script`, weighted the way Bitcoin Core's
+/// `GetSigOpCount` does: `OP_CHECKSIG`/`OP_CHECKSIGVERIFY` count 1,
+/// `OP_CHECKMULTISIG`/`OP_CHECKMULTISIGVERIFY` count 20, unless `accurate` is
+/// set and the multisig op is immediately preceded by a small-integer push,
+/// in which case that count is used instead (as is always the case inside a
+/// witness program, where `accurate` should be `true`).
+fn script_sigop_count(script: &bitcoin::Script, accurate: bool) -> u64 {
+    use bitcoin::blockdata::opcodes::all as opcodes;
+    use bitcoin::blockdata::script::Instruction;
+
+    // the small-integer push (OP_1..OP_16) immediately preceding the
+    // instruction under consideration, if any -- used to size an `accurate`
+    // OP_CHECKMULTISIG rather than assuming the 20-sigop worst case.
+    let mut pending_small_int: Option<u64> = None;
+    let mut count = 0u64;
+    for instruction in script.instructions().flatten() {
+        let mut this_small_int = None;
+        match instruction {
+            Instruction::Op(opcodes::OP_CHECKSIG) | Instruction::Op(opcodes::OP_CHECKSIGVERIFY) => {
+                count += 1;
+            }
+            Instruction::Op(opcodes::OP_CHECKMULTISIG)
+            | Instruction::Op(opcodes::OP_CHECKMULTISIGVERIFY) => {
+                count += match (accurate, pending_small_int) {
+                    (true, Some(n)) => n,
+                    _ => 20,
+                };
+            }
+            Instruction::Op(op) => {
+                let val = op.into_u8();
+                if (opcodes::OP_PUSHNUM_1.into_u8()..=opcodes::OP_PUSHNUM_16.into_u8())
+                    .contains(&val)
+                {
+                    this_small_int = Some((val - opcodes::OP_PUSHNUM_1.into_u8() + 1) as u64);
+                }
+            }
+            Instruction::PushBytes(_) => {}
+        }
+        pending_small_int = this_small_int;
+    }
+    count
+}
+
+/// Errors that can arise while converting a `Template` into a PSBT
+#[derive(Debug)]
+pub enum TemplatePsbtError {
+    /// the underlying `rust-bitcoin` psbt construction failed
+    Psbt(psbt::Error),
+}
+
+impl fmt::Display for TemplatePsbtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplatePsbtError::Psbt(e) => write!(f, "failed to build PSBT: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplatePsbtError {}
+
+impl From<psbt::Error> for TemplatePsbtError {
+    fn from(e: psbt::Error) -> Self {
+        TemplatePsbtError::Psbt(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::builder::Builder;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut, Witness};
+
+    fn sample_tx() -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn to_psbt_round_trips_output_witness_and_redeem_scripts() {
+        let witness_script = Script::from(vec![0x51]);
+        let redeem_script = Script::from(vec![0x52]);
+        let output = Output::new(Script::new(), Amount::from_sat(100_000))
+            .with_witness_script(witness_script.clone())
+            .with_redeem_script(redeem_script.clone());
+
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            .build()
+            .expect("single-input, in-bounds template builds");
+
+        let psbt = template.to_psbt().expect("to_psbt succeeds");
+
+        assert_eq!(psbt.unsigned_tx, template.tx);
+        assert!(psbt.inputs[0].witness_utxo.is_none());
+        assert_eq!(psbt.outputs[0].witness_script, Some(witness_script));
+        assert_eq!(psbt.outputs[0].redeem_script, Some(redeem_script));
+    }
+
+    #[test]
+    fn fee_saturates_to_zero_when_max_is_less_than_total_amount() {
+        let output = Output::new(Script::new(), Amount::from_sat(100_000));
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            .set_max_amount(Amount::from_sat(50_000))
+            .build()
+            .expect("template builds even though max underpays the outputs");
+
+        assert_eq!(template.fee(), Amount::from_sat(0));
+    }
+
+    #[test]
+    fn fee_is_max_minus_total_amount_when_max_overpays() {
+        let output = Output::new(Script::new(), Amount::from_sat(100_000));
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            .set_max_amount(Amount::from_sat(150_000))
+            .build()
+            .expect("template builds");
+
+        assert_eq!(template.fee(), Amount::from_sat(50_000));
+    }
+
+    #[test]
+    fn feerate_sats_per_vbyte_is_zero_for_a_zero_weight_transaction() {
+        assert_eq!(feerate_sats_per_vbyte(Amount::from_sat(1_000), 0), 0.0);
+    }
+
+    #[test]
+    fn feerate_sats_per_vbyte_divides_fee_by_vsize() {
+        // 400 weight units is 100 vbytes; 1000 sats / 100 vbytes = 10 sat/vbyte
+        assert_eq!(feerate_sats_per_vbyte(Amount::from_sat(1_000), 400), 10.0);
+    }
+
+    #[test]
+    fn meets_min_feerate_is_true_when_unset() {
+        let output = Output::new(Script::new(), Amount::from_sat(100_000));
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            .build()
+            .expect("template builds");
+
+        assert!(template.meets_min_feerate());
+    }
+
+    #[test]
+    fn meets_min_feerate_rejects_an_underpaying_template() {
+        let output = Output::new(Script::new(), Amount::from_sat(100_000));
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            // max == total_amount, so this template pays a 0 sat/vbyte fee
+            .set_min_feerate(Amount::from_sat(1))
+            .build()
+            .expect("template builds");
+
+        assert!(!template.meets_min_feerate());
+    }
+
+    #[test]
+    fn script_sigop_count_counts_checksig_as_one_either_way() {
+        use bitcoin::blockdata::opcodes::all as opcodes;
+        let script = Script::from(vec![opcodes::OP_CHECKSIG.into_u8()]);
+        assert_eq!(script_sigop_count(&script, false), 1);
+        assert_eq!(script_sigop_count(&script, true), 1);
+    }
+
+    #[test]
+    fn script_sigop_count_checkmultisig_uses_accurate_small_int_push() {
+        use bitcoin::blockdata::opcodes::all as opcodes;
+        // OP_1 OP_CHECKMULTISIG -- a 1-of-1 multisig
+        let script = Script::from(vec![
+            opcodes::OP_PUSHNUM_1.into_u8(),
+            opcodes::OP_CHECKMULTISIG.into_u8(),
+        ]);
+        assert_eq!(script_sigop_count(&script, false), 20);
+        assert_eq!(script_sigop_count(&script, true), 1);
+    }
+
+    #[test]
+    fn sigops_cost_ignores_outputs_witness_scripts() {
+        // a witness_script attached to an output belongs to a future child
+        // transaction, not to this one, and must not inflate this
+        // Template's own sigops_cost.
+        let witness_script = Script::from(vec![
+            bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG.into_u8()
+        ]);
+        let output = Output::new(Script::new(), Amount::from_sat(100_000))
+            .with_witness_script(witness_script);
+        let template = Builder::new(sample_tx())
+            .add_output(output)
+            .build()
+            .expect("template builds");
+
+        assert_eq!(template.sigops_cost(), 0);
+    }
 }
@@ -0,0 +1,81 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a single output of a Template, along with Sapio-specific metadata about it
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Metadata for a single `Output`, analogous to `TemplateMetadata` but scoped
+/// to one output rather than the whole transaction.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct OutputMeta {
+    /// A label for this output
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub label: Option<String>,
+    /// catch all map for future metadata....
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl OutputMeta {
+    /// create a new, empty `OutputMeta`
+    pub fn new() -> Self {
+        OutputMeta::default()
+    }
+}
+
+/// A single output of a `Template`, pairing the on-chain `script_pubkey`/`amount`
+/// with any Sapio-specific metadata about it.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Output {
+    /// the amount being sent to this output
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "i64")]
+    pub amount: Amount,
+    /// the script_pubkey this output pays to
+    pub script_pubkey: Script,
+    /// any metadata fields attached to this output
+    #[serde(default)]
+    pub metadata: OutputMeta,
+    /// the witness script that satisfies this output's `script_pubkey`, if it
+    /// is a p2wsh (or p2sh-wrapped p2wsh) output. Used to populate a PSBT.
+    /// (TODO: taproot outputs aren't represented yet -- there's no
+    /// `tap_internal_key`/`tap_tree` counterpart for a p2tr `script_pubkey`.)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub witness_script: Option<Script>,
+    /// the redeem script that satisfies this output's `script_pubkey`, if it
+    /// is a p2sh output. Used to populate a PSBT.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub redeem_script: Option<Script>,
+}
+
+impl Output {
+    /// create a new `Output` paying `amount` to `script_pubkey`, with no metadata
+    pub fn new(script_pubkey: Script, amount: Amount) -> Self {
+        Output {
+            amount,
+            script_pubkey,
+            metadata: OutputMeta::new(),
+            witness_script: None,
+            redeem_script: None,
+        }
+    }
+
+    /// attach the witness script that satisfies this output's `script_pubkey`
+    pub fn with_witness_script(mut self, witness_script: Script) -> Self {
+        self.witness_script = Some(witness_script);
+        self
+    }
+
+    /// attach the redeem script that satisfies this output's `script_pubkey`
+    pub fn with_redeem_script(mut self, redeem_script: Script) -> Self {
+        self.redeem_script = Some(redeem_script);
+        self
+    }
+}
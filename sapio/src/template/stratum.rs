@@ -0,0 +1,157 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! export a Template's outputs as Stratum V2 Template Distribution
+//! `NewTemplate.coinbase_tx_outputs` bytes
+use super::builder::Builder;
+use super::Template;
+use bitcoin::consensus::encode;
+use bitcoin::util::amount::Amount;
+use std::fmt;
+
+/// Errors that can arise while exporting a Template as Stratum V2 coinbase
+/// outputs
+#[derive(Debug)]
+pub enum StratumExportError {
+    /// the template's outputs sum to more than the block subsidy available
+    /// to spend them from
+    ExceedsSubsidy {
+        /// the total paid out by this template's outputs
+        total: Amount,
+        /// the subsidy the caller said was available
+        subsidy: Amount,
+    },
+}
+
+impl fmt::Display for StratumExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StratumExportError::ExceedsSubsidy { total, subsidy } => write!(
+                f,
+                "template outputs total {} sats, which exceeds the {} sat subsidy available",
+                total.as_sat(),
+                subsidy.as_sat()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StratumExportError {}
+
+impl Template {
+    /// Serialize this template's outputs as Stratum V2 Template Distribution
+    /// `NewTemplate.coinbase_tx_outputs` bytes: a count-prefixed list of
+    /// outputs, each as an 8-byte LE value followed by a varint-length-prefixed
+    /// script -- i.e. exactly how `bitcoin::TxOut`s consensus-encode, so pool
+    /// software can append the bytes verbatim onto its coinbase transaction.
+    /// `subsidy` is the block subsidy available to fund the coinbase; an
+    /// error is returned rather than silently overspending it.
+    ///
+    /// Sourced from `tx.output` rather than `outputs` -- `tx.output` is what
+    /// `self.ctv` actually commits to, and `builder::Builder::build` already
+    /// guarantees the two agree, so a miner appending these bytes to its
+    /// coinbase can't silently diverge from what this template's CTV-enforced
+    /// children expect to be spending.
+    pub fn to_stratum_v2_coinbase_outputs(
+        &self,
+        subsidy: Amount,
+    ) -> Result<Vec<u8>, StratumExportError> {
+        let total = self.total_amount();
+        if total > subsidy {
+            return Err(StratumExportError::ExceedsSubsidy { total, subsidy });
+        }
+        Ok(encode::serialize(&self.tx.output))
+    }
+}
+
+impl Builder {
+    /// finalize this `Builder` directly into Stratum V2 coinbase output
+    /// bytes, as `build().to_stratum_v2_coinbase_outputs(subsidy)` would.
+    pub fn to_stratum_v2_coinbase_outputs(
+        self,
+        subsidy: Amount,
+    ) -> Result<Vec<u8>, super::builder::TemplateBuildError> {
+        Ok(self.build()?.to_stratum_v2_coinbase_outputs(subsidy)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::output::Output;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut, Witness};
+
+    fn sample_tx_with_input(outputs: Vec<TxOut>) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: Witness::default(),
+            }],
+            output: outputs,
+        }
+    }
+
+    #[test]
+    fn exports_empty_outputs_as_a_zero_count_prefix() {
+        let template = Builder::new(sample_tx_with_input(vec![]))
+            .build()
+            .expect("an empty-output, single-input template builds");
+
+        let bytes = template
+            .to_stratum_v2_coinbase_outputs(Amount::from_sat(0))
+            .expect("zero outputs can never exceed any subsidy");
+
+        // a consensus `VarInt` count of 0 is a single `0x00` byte
+        assert_eq!(bytes, vec![0x00]);
+    }
+
+    #[test]
+    fn rejects_outputs_exceeding_the_supplied_subsidy() {
+        let output = Output::new(Script::new(), Amount::from_sat(100_000));
+        let tx = sample_tx_with_input(vec![TxOut {
+            value: 100_000,
+            script_pubkey: Script::new(),
+        }]);
+        let template = Builder::new(tx)
+            .add_output(output)
+            .build()
+            .expect("single-output, single-input template builds");
+
+        let err = template
+            .to_stratum_v2_coinbase_outputs(Amount::from_sat(99_999))
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            StratumExportError::ExceedsSubsidy { total, subsidy }
+                if total == Amount::from_sat(100_000) && subsidy == Amount::from_sat(99_999)
+        ));
+    }
+
+    #[test]
+    fn sources_bytes_from_tx_output_not_the_outputs_metadata_vec() {
+        let script_pubkey = Script::from(vec![0x51]);
+        let output = Output::new(script_pubkey.clone(), Amount::from_sat(100_000));
+        let tx = sample_tx_with_input(vec![TxOut {
+            value: 100_000,
+            script_pubkey: script_pubkey.clone(),
+        }]);
+        let template = Builder::new(tx.clone())
+            .add_output(output)
+            .build()
+            .expect("single-output, single-input template builds");
+
+        let bytes = template
+            .to_stratum_v2_coinbase_outputs(Amount::from_sat(100_000))
+            .expect("outputs don't exceed the subsidy");
+
+        assert_eq!(bytes, encode::serialize(&tx.output));
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! optional integration for funding and broadcasting a `Template` against a
+//! Bitcoin Core node over JSON-RPC. Gated behind the `rpc-submission`
+//! feature since it pulls in `bitcoincore-rpc` and requires a live node.
+#![cfg(feature = "rpc-submission")]
+use super::builder::get_ctv_hash;
+use super::Template;
+use bitcoin::{Address, Script, Transaction, Txid};
+use bitcoincore_rpc::{self, Client, RpcApi};
+use std::fmt;
+
+/// Errors that can arise while funding and broadcasting a `Template`
+#[derive(Debug)]
+pub enum SubmissionError {
+    /// no unspent output paying the `script_pubkey` passed to
+    /// `fund_and_broadcast` could be found or created for the amount
+    /// `Template::total_amount` requires
+    NoFundingUtxo {
+        /// the scriptPubKey a funding UTXO was required to pay
+        script_pubkey: Script,
+    },
+    /// the `script_pubkey` passed to `fund_and_broadcast` isn't an address
+    /// Bitcoin Core knows how to
+    /// derive (e.g. a bare, non-standard script)
+    UnspendableScriptPubkey(Script),
+    /// after substituting the funding UTXO, the resulting transaction's CTV
+    /// hash at `template.ctv_index` didn't match `template.ctv`. This would
+    /// indicate the template or funding input was built incorrectly.
+    CtvMismatch {
+        /// the hash actually committed to by the assembled transaction
+        computed: bitcoin::hashes::sha256::Hash,
+        /// the hash the `Template` expected
+        expected: bitcoin::hashes::sha256::Hash,
+    },
+    /// the underlying RPC call to Bitcoin Core failed
+    Rpc(bitcoincore_rpc::Error),
+}
+
+impl fmt::Display for SubmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmissionError::NoFundingUtxo { script_pubkey } => write!(
+                f,
+                "no funding UTXO available or creatable for script_pubkey {}",
+                script_pubkey
+            ),
+            SubmissionError::UnspendableScriptPubkey(s) => {
+                write!(f, "cannot derive an address to fund script_pubkey {}", s)
+            }
+            SubmissionError::CtvMismatch { computed, expected } => write!(
+                f,
+                "assembled transaction commits to {} but template.ctv is {}",
+                computed, expected
+            ),
+            SubmissionError::Rpc(e) => write!(f, "bitcoin core rpc error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SubmissionError {}
+
+impl From<bitcoincore_rpc::Error> for SubmissionError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        SubmissionError::Rpc(e)
+    }
+}
+
+/// The outcome of funding and broadcasting a single `Template`, with enough
+/// detail at each step for a caller to chain onto a child `Template` once
+/// this one confirms.
+#[derive(Debug, Clone)]
+pub struct SubmissionResult {
+    /// the txid of the transaction that created the funding UTXO this
+    /// template's input spends
+    pub funding_txid: Txid,
+    /// the fully assembled, CTV-verified spending transaction
+    pub spending_tx: Transaction,
+    /// `spending_tx`, as returned in full by Bitcoin Core's verbose
+    /// `getrawtransaction` once broadcast (all outputs, not just the first)
+    pub decoded: bitcoincore_rpc::json::GetRawTransactionResult,
+    /// the result of `testmempoolaccept`-ing `spending_tx`, captured before
+    /// it is broadcast. `spending_tx` is broadcast whatever this says; if
+    /// `sendrawtransaction` then rejects it, `fund_and_broadcast` returns
+    /// `SubmissionError::Rpc` and no `SubmissionResult` is produced
+    pub mempool_accept: bitcoincore_rpc::json::TestMempoolAcceptResult,
+    /// the txid Bitcoin Core accepted `spending_tx` under, once broadcast
+    pub broadcast_txid: Txid,
+}
+
+/// Locate a UTXO paying at least `template.total_amount()` to
+/// `script_pubkey` (or create one paying exactly that, via
+/// `sendtoaddress`), fund `template`'s input at `template.ctv_index` with it,
+/// verify the resulting transaction still commits to `template.ctv`, and
+/// broadcast it via `sendrawtransaction`. `network` selects which network
+/// `script_pubkey` should be interpreted under (mainnet, testnet, signet, or
+/// regtest); covenant testing typically runs against regtest or signet.
+///
+/// Only the input at `template.ctv_index` is funded -- a multi-input
+/// template's other inputs are assumed to already be populated on
+/// `template.tx` by the caller. To walk a covenant tree of linked templates,
+/// call this once per parent/child pair, waiting for each `broadcast_txid`
+/// to confirm before funding its children with it.
+pub fn fund_and_broadcast(
+    client: &Client,
+    template: &Template,
+    script_pubkey: &Script,
+    network: bitcoin::Network,
+) -> Result<SubmissionResult, SubmissionError> {
+    let amount = template.total_amount();
+
+    let address = Address::from_script(script_pubkey, network)
+        .ok_or_else(|| SubmissionError::UnspendableScriptPubkey(script_pubkey.clone()))?;
+
+    let unspent = client.list_unspent(None, None, None, None, None)?;
+    let existing = unspent
+        .into_iter()
+        .find(|u| u.script_pub_key == *script_pubkey && u.amount.as_sat() >= amount.as_sat());
+
+    let (funding_txid, vout) = if let Some(utxo) = existing {
+        (utxo.txid, utxo.vout)
+    } else {
+        let txid = client.send_to_address(&address, amount, None, None, None, None, None, None)?;
+        let vout = client
+            .get_raw_transaction_info(&txid, None)?
+            .vout
+            .into_iter()
+            .position(|o| o.script_pub_key.hex == script_pubkey.to_bytes())
+            .ok_or(SubmissionError::NoFundingUtxo {
+                script_pubkey: script_pubkey.clone(),
+            })? as u32;
+        (txid, vout)
+    };
+
+    let mut spending_tx = template.tx.clone();
+    let idx = template.ctv_index as usize;
+    spending_tx.input[idx].previous_output = bitcoin::OutPoint {
+        txid: funding_txid,
+        vout,
+    };
+
+    let computed = get_ctv_hash(&spending_tx, template.ctv_index);
+    if computed != template.ctv {
+        return Err(SubmissionError::CtvMismatch {
+            computed,
+            expected: template.ctv,
+        });
+    }
+
+    let mempool_accept = client
+        .test_mempool_accept(&[&spending_tx])?
+        .into_iter()
+        .next()
+        .expect("testmempoolaccept returns one result per input transaction");
+
+    let broadcast_txid = client.send_raw_transaction(&spending_tx)?;
+    let decoded = client.get_raw_transaction_info(&broadcast_txid, None)?;
+
+    Ok(SubmissionResult {
+        funding_txid,
+        spending_tx,
+        decoded,
+        mempool_accept,
+        broadcast_txid,
+    })
+}